@@ -1,9 +1,11 @@
 // src/lib.rs
 
 use wasm_bindgen::prelude::*;
-use image::{DynamicImage, ImageBuffer, ExtendedColorType, ImageEncoder};
+use image::{ImageBuffer, ExtendedColorType, ImageEncoder, Rgb, Rgba};
 use image::codecs::png::{CompressionType, FilterType, PngEncoder};
+use image::codecs::webp::WebPEncoder;
 use js_sys::Uint8Array;
+use png::{ColorType as PngColorType, Decoder as PngDecoder, Transformations};
 use wee_alloc::WeeAlloc;
 
 #[global_allocator]
@@ -17,6 +19,7 @@ pub struct Metadata {
     width: u32,
     height: u32,
     channels: u8,
+    format: String,
 }
 
 #[wasm_bindgen]
@@ -38,6 +41,22 @@ impl Metadata {
     pub fn channels(&self) -> u8 {
         self.channels
     }
+
+    /// Gets the detected source format of the image (e.g. "png", "jpeg", "webp").
+    #[wasm_bindgen(getter)]
+    pub fn format(&self) -> String {
+        self.format.clone()
+    }
+}
+
+/// Maps an `image::ImageFormat` to the lowercase label exposed on `Metadata`.
+fn format_label(format: image::ImageFormat) -> &'static str {
+    match format {
+        image::ImageFormat::Png => "png",
+        image::ImageFormat::Jpeg => "jpeg",
+        image::ImageFormat::WebP => "webp",
+        _ => "unknown",
+    }
 }
 
 /// Assembled image data including raw pixels and metadata.
@@ -63,44 +82,207 @@ impl AssembledImageData {
     }
 }
 
-/// Loads image data from PNG bytes, processes it, and returns assembled image data.
+/// Loads image data from PNG or lossless WebP bytes, processes it, and returns
+/// assembled image data.
+///
+/// The input is sniffed with `image::guess_format` and decoded as WebP when
+/// detected; anything else is decoded as PNG. If the source image carries an
+/// alpha channel, the full RGBA plane is preserved (`Metadata.channels == 4`);
+/// otherwise the image is decoded as RGB.
+///
+/// Returns a catchable `JsError` instead of aborting the WASM instance if the
+/// cover image fails to decode.
 #[wasm_bindgen]
-pub fn load_image_assembled(png_data: &[u8]) -> AssembledImageData {
-    // Attempt to load the image from memory with specified format to speed up loading.
-    let img = image::load_from_memory_with_format(png_data, image::ImageFormat::Png)
-        .unwrap_or_else(|_| DynamicImage::new_rgb8(1, 1));
-    let rgb_img = img.to_rgb8();
+pub fn load_image_assembled(png_data: &[u8]) -> Result<AssembledImageData, JsError> {
+    let format = match image::guess_format(png_data) {
+        Ok(image::ImageFormat::WebP) => image::ImageFormat::WebP,
+        _ => image::ImageFormat::Png,
+    };
+
+    let img = image::load_from_memory_with_format(png_data, format)
+        .map_err(|err| JsError::new(&format!("Failed to decode image: {err}")))?;
+
+    let (data, channels) = if img.color().has_alpha() {
+        (img.to_rgba8().into_raw(), 4)
+    } else {
+        (img.to_rgb8().into_raw(), 3)
+    };
 
     // Construct Metadata.
     let metadata = Metadata {
-        width: rgb_img.width(),
-        height: rgb_img.height(),
-        channels: 3, // RGB
+        width: img.width(),
+        height: img.height(),
+        channels,
+        format: format_label(format).to_string(),
     };
 
     // Assemble the image data.
-    AssembledImageData {
-        data: rgb_img.into_raw(),
-        metadata,
+    Ok(AssembledImageData { data, metadata })
+}
+
+/// Loads image data from PNG, JPEG, or WebP bytes by sniffing the magic bytes,
+/// processes it, and returns assembled image data with the detected format.
+///
+/// Falls back to treating the input as PNG if the format cannot be guessed, but a
+/// genuine decode failure is returned as a catchable `JsError` instead of being
+/// swallowed into a silent black pixel.
+#[wasm_bindgen]
+pub fn load_image_assembled_auto(image_data: &[u8]) -> Result<AssembledImageData, JsError> {
+    let format = image::guess_format(image_data).unwrap_or(image::ImageFormat::Png);
+    let img = image::load_from_memory_with_format(image_data, format)
+        .map_err(|err| JsError::new(&format!("Failed to decode image: {err}")))?;
+
+    let (data, channels) = if img.color().has_alpha() {
+        (img.to_rgba8().into_raw(), 4)
+    } else {
+        (img.to_rgb8().into_raw(), 3)
+    };
+
+    let metadata = Metadata {
+        width: img.width(),
+        height: img.height(),
+        channels,
+        format: format_label(format).to_string(),
+    };
+
+    Ok(AssembledImageData { data, metadata })
+}
+
+/// Loads a PNG by driving the `png` crate's reader one scanline at a time instead
+/// of materializing a `DynamicImage` alongside the decoded buffer, which halves
+/// peak memory for large covers under a WASM linear-memory budget.
+///
+/// Interlaced PNGs cannot be read in scanline order, so they fall back to
+/// [`load_image_assembled`].
+#[wasm_bindgen]
+pub fn load_image_assembled_streaming(png_data: &[u8]) -> Result<AssembledImageData, JsError> {
+    let mut decoder = PngDecoder::new(png_data);
+    decoder.set_transformations(Transformations::normalize_to_color8());
+
+    let mut reader = match decoder.read_info() {
+        Ok(reader) => reader,
+        Err(_) => return load_image_assembled(png_data),
+    };
+
+    if reader.info().interlaced {
+        return load_image_assembled(png_data);
+    }
+
+    let (color_type, _) = reader.output_color_type();
+    let (width, height) = (reader.info().width, reader.info().height);
+    let mut buf = vec![0u8; reader.output_buffer_size()];
+    let mut filled = 0;
+    loop {
+        match reader.next_row() {
+            Ok(Some(row)) => {
+                let line = row.data();
+                buf[filled..filled + line.len()].copy_from_slice(line);
+                filled += line.len();
+            }
+            Ok(None) => break,
+            Err(_) => return load_image_assembled(png_data),
+        }
+    }
+    buf.truncate(filled);
+
+    // `normalize_to_color8` (EXPAND | STRIP_16) expands indexed/palette PNGs to
+    // RGB(A), but it does not convert true grayscale or grayscale+alpha PNGs -
+    // those still need to be widened here so `buf`'s layout always matches the
+    // reported channel count.
+    let (data, channels) = match color_type {
+        PngColorType::Grayscale => {
+            let expanded = buf.iter().flat_map(|&g| [g, g, g]).collect();
+            (expanded, 3)
+        }
+        PngColorType::GrayscaleAlpha => {
+            let expanded = buf
+                .chunks_exact(2)
+                .flat_map(|px| [px[0], px[0], px[0], px[1]])
+                .collect();
+            (expanded, 4)
+        }
+        PngColorType::Rgba => (buf, 4),
+        _ => (buf, 3),
+    };
+
+    let metadata = Metadata {
+        width,
+        height,
+        channels,
+        format: format_label(image::ImageFormat::Png).to_string(),
+    };
+
+    Ok(AssembledImageData { data, metadata })
+}
+
+/// Maps a `filter_strategy` knob to a PNG `FilterType`, defaulting to `NoFilter`
+/// when given a value out of range (mirrors how `compression_level` defaults to
+/// `Fast`).
+fn filter_type_from_strategy(filter_strategy: u8) -> FilterType {
+    match filter_strategy {
+        0 => FilterType::NoFilter,
+        1 => FilterType::Sub,
+        2 => FilterType::Up,
+        3 => FilterType::Avg,
+        4 => FilterType::Paeth,
+        5 => FilterType::Adaptive,
+        _ => FilterType::NoFilter,
     }
 }
 
+/// Validates that `channels` is one of the layouts the encoders below actually
+/// support, so an unsupported value (e.g. 0, 1, 2, 5) is rejected up front with
+/// a message naming the bad value instead of silently falling through to RGB
+/// and failing later with a generic `ImageBuffer` or length-mismatch error.
+fn validate_channels(channels: u8) -> Result<(), JsError> {
+    if channels != 3 && channels != 4 {
+        return Err(JsError::new(&format!(
+            "channels must be 3 (RGB) or 4 (RGBA), got {channels}"
+        )));
+    }
+    Ok(())
+}
+
+/// Validates that `raw_data` matches the declared dimensions and channel count,
+/// so a mismatch is reported as a catchable error instead of a panicking
+/// `ImageBuffer::from_raw` unwrap.
+fn validate_raw_data_len(raw_data: &[u8], width: u32, height: u32, channels: u8) -> Result<(), JsError> {
+    let expected = (width as usize) * (height as usize) * (channels as usize);
+    if raw_data.len() != expected {
+        return Err(JsError::new(&format!(
+            "raw_data length {} does not match {}x{}x{} = {}",
+            raw_data.len(),
+            width,
+            height,
+            channels,
+            expected
+        )));
+    }
+    Ok(())
+}
+
 /// Writes image data to PNG bytes with specified configurations.
+///
+/// `channels` must be 3 (RGB) or 4 (RGBA) and must match the layout of `raw_data`.
+/// `filter_strategy` trades encode time for smaller carrier files: 0 = NoFilter,
+/// 1 = Sub, 2 = Up, 3 = Avg, 4 = Paeth, 5 = Adaptive.
+///
+/// Returns a catchable `JsError` instead of aborting the WASM instance on a
+/// dimension/channel mismatch or PNG encoding failure.
 #[wasm_bindgen]
 pub fn write_image_data(
     raw_data: Vec<u8>, // Take ownership to avoid cloning
     width: u32,
     height: u32,
+    channels: u8,
     compression_level: u8,
-) -> Uint8Array {
-    // Create an ImageBuffer from the raw data without cloning
-    let img_buffer = ImageBuffer::from_raw(width, height, raw_data)
-        .expect("Failed to create ImageBuffer");
-
-    let img = DynamicImage::ImageRgb8(img_buffer);
+    filter_strategy: u8,
+) -> Result<Uint8Array, JsError> {
+    validate_channels(channels)?;
+    validate_raw_data_len(&raw_data, width, height, channels)?;
 
     // Initialize a buffer with a reasonable capacity
-    let mut buffer = Vec::with_capacity((width * height * 3) as usize);
+    let mut buffer = Vec::with_capacity((width * height * channels as u32) as usize);
 
     // Determine the compression type based on the compression_level
     let compression = match compression_level {
@@ -110,14 +292,204 @@ pub fn write_image_data(
         _ => CompressionType::Fast,          // Default to fast if out of range
     };
 
-    // Use a simpler filter type for faster encoding
-    let png_encoder = PngEncoder::new_with_quality(&mut buffer, compression, FilterType::NoFilter);
+    let filter = filter_type_from_strategy(filter_strategy);
+    let png_encoder = PngEncoder::new_with_quality(&mut buffer, compression, filter);
 
-    // Write the image to the buffer
-    png_encoder
-        .write_image(&img.to_rgb8(), width, height, ExtendedColorType::Rgb8)
-        .expect("Failed to encode PNG");
+    match channels {
+        4 => {
+            let img_buffer: ImageBuffer<Rgba<u8>, Vec<u8>> = ImageBuffer::from_raw(width, height, raw_data)
+                .ok_or_else(|| JsError::new("Failed to create ImageBuffer"))?;
+            png_encoder
+                .write_image(&img_buffer, width, height, ExtendedColorType::Rgba8)
+                .map_err(|err| JsError::new(&format!("Failed to encode PNG: {err}")))?;
+        }
+        _ => {
+            let img_buffer: ImageBuffer<Rgb<u8>, Vec<u8>> = ImageBuffer::from_raw(width, height, raw_data)
+                .ok_or_else(|| JsError::new("Failed to create ImageBuffer"))?;
+            png_encoder
+                .write_image(&img_buffer, width, height, ExtendedColorType::Rgb8)
+                .map_err(|err| JsError::new(&format!("Failed to encode PNG: {err}")))?;
+        }
+    }
 
     // Transfer ownership to JS without copying
-    Uint8Array::from(&buffer[..])
+    Ok(Uint8Array::from(&buffer[..]))
+}
+
+/// Writes image data to lossless WebP bytes.
+///
+/// Lossless WebP preserves every pixel exactly, like PNG, but typically produces
+/// 20-30% smaller files, which matters for payloads that must survive re-encoding
+/// bit-for-bit. `channels` must be 3 (RGB) or 4 (RGBA) and must match `raw_data`.
+///
+/// Returns a catchable `JsError` instead of aborting the WASM instance on a
+/// dimension/channel mismatch or WebP encoding failure.
+#[wasm_bindgen]
+pub fn write_image_data_webp(
+    raw_data: Vec<u8>, // Take ownership to avoid cloning
+    width: u32,
+    height: u32,
+    channels: u8,
+) -> Result<Uint8Array, JsError> {
+    validate_channels(channels)?;
+    validate_raw_data_len(&raw_data, width, height, channels)?;
+
+    // Initialize a buffer with a reasonable capacity
+    let mut buffer = Vec::with_capacity((width * height * channels as u32) as usize);
+
+    let webp_encoder = WebPEncoder::new_lossless(&mut buffer);
+
+    match channels {
+        4 => {
+            let img_buffer: ImageBuffer<Rgba<u8>, Vec<u8>> = ImageBuffer::from_raw(width, height, raw_data)
+                .ok_or_else(|| JsError::new("Failed to create ImageBuffer"))?;
+            webp_encoder
+                .write_image(&img_buffer, width, height, ExtendedColorType::Rgba8)
+                .map_err(|err| JsError::new(&format!("Failed to encode WebP: {err}")))?;
+        }
+        _ => {
+            let img_buffer: ImageBuffer<Rgb<u8>, Vec<u8>> = ImageBuffer::from_raw(width, height, raw_data)
+                .ok_or_else(|| JsError::new("Failed to create ImageBuffer"))?;
+            webp_encoder
+                .write_image(&img_buffer, width, height, ExtendedColorType::Rgb8)
+                .map_err(|err| JsError::new(&format!("Failed to encode WebP: {err}")))?;
+        }
+    }
+
+    // Copy the encoded bytes into a new JS-owned Uint8Array
+    Ok(Uint8Array::from(&buffer[..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encodes a small synthetic PNG for exercising `load_image_assembled_streaming`
+    /// against every `png::ColorType` it has to branch on.
+    fn encode_test_png(
+        width: u32,
+        height: u32,
+        color_type: png::ColorType,
+        palette: Option<Vec<u8>>,
+        trns: Option<Vec<u8>>,
+        interlaced: bool,
+        raw: &[u8],
+    ) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        {
+            let mut info = png::Info::with_size(width, height);
+            info.color_type = color_type;
+            info.bit_depth = png::BitDepth::Eight;
+            info.interlaced = interlaced;
+            info.palette = palette.map(std::borrow::Cow::Owned);
+            info.trns = trns.map(std::borrow::Cow::Owned);
+
+            let encoder = png::Encoder::with_info(&mut bytes, info).expect("failed to build encoder");
+            let mut writer = encoder.write_header().expect("failed to write PNG header");
+            writer.write_image_data(raw).expect("failed to write PNG data");
+        }
+        bytes
+    }
+
+    #[test]
+    fn streaming_grayscale_channels_match_data_len() {
+        let raw = vec![10u8, 20, 30, 40]; // 2x2, 1 byte/pixel
+        let png_data = encode_test_png(2, 2, png::ColorType::Grayscale, None, None, false, &raw);
+
+        let assembled = load_image_assembled_streaming(&png_data).expect("decode failed");
+        let metadata = assembled.metadata();
+        assert_eq!(metadata.channels(), 3);
+        assert_eq!(
+            assembled.data.len() as u32,
+            metadata.width() * metadata.height() * metadata.channels() as u32
+        );
+    }
+
+    #[test]
+    fn streaming_grayscale_alpha_channels_match_data_len() {
+        let raw = vec![10u8, 255, 20, 128, 30, 64, 40, 0]; // 2x2, 2 bytes/pixel
+        let png_data = encode_test_png(2, 2, png::ColorType::GrayscaleAlpha, None, None, false, &raw);
+
+        let assembled = load_image_assembled_streaming(&png_data).expect("decode failed");
+        let metadata = assembled.metadata();
+        assert_eq!(metadata.channels(), 4);
+        assert_eq!(
+            assembled.data.len() as u32,
+            metadata.width() * metadata.height() * metadata.channels() as u32
+        );
+    }
+
+    #[test]
+    fn streaming_rgb_channels_match_data_len() {
+        let raw = vec![255u8, 0, 0, 0, 255, 0, 0, 0, 255, 255, 255, 255]; // 2x2, 3 bytes/pixel
+        let png_data = encode_test_png(2, 2, png::ColorType::Rgb, None, None, false, &raw);
+
+        let assembled = load_image_assembled_streaming(&png_data).expect("decode failed");
+        let metadata = assembled.metadata();
+        assert_eq!(metadata.channels(), 3);
+        assert_eq!(
+            assembled.data.len() as u32,
+            metadata.width() * metadata.height() * metadata.channels() as u32
+        );
+    }
+
+    #[test]
+    fn streaming_rgba_channels_match_data_len() {
+        let raw = vec![
+            255u8, 0, 0, 255, 0, 255, 0, 128, 0, 0, 255, 64, 255, 255, 255, 0,
+        ]; // 2x2, 4 bytes/pixel
+        let png_data = encode_test_png(2, 2, png::ColorType::Rgba, None, None, false, &raw);
+
+        let assembled = load_image_assembled_streaming(&png_data).expect("decode failed");
+        let metadata = assembled.metadata();
+        assert_eq!(metadata.channels(), 4);
+        assert_eq!(
+            assembled.data.len() as u32,
+            metadata.width() * metadata.height() * metadata.channels() as u32
+        );
+    }
+
+    #[test]
+    fn streaming_indexed_with_trns_channels_match_data_len() {
+        let raw = vec![0u8, 1, 1, 0]; // 2x2, one palette index byte/pixel
+        let palette = vec![255u8, 0, 0, 0, 255, 0]; // two palette entries (red, green)
+        let trns = vec![255u8, 0]; // entry 0 opaque, entry 1 fully transparent
+        let png_data = encode_test_png(
+            2,
+            2,
+            png::ColorType::Indexed,
+            Some(palette),
+            Some(trns),
+            false,
+            &raw,
+        );
+
+        let assembled = load_image_assembled_streaming(&png_data).expect("decode failed");
+        let metadata = assembled.metadata();
+        // normalize_to_color8's EXPAND transform resolves an indexed+tRNS image
+        // straight to RGBA, so the decoded color_type is already Rgba here.
+        assert_eq!(metadata.channels(), 4);
+        assert_eq!(
+            assembled.data.len() as u32,
+            metadata.width() * metadata.height() * metadata.channels() as u32
+        );
+    }
+
+    #[test]
+    fn streaming_interlaced_png_falls_back_to_load_image_assembled() {
+        // A 1x1 image is the one case where Adam7's pass 1 covers the whole
+        // image and every other pass is empty, so a plain scanline write
+        // happens to double as valid interlaced data - letting this test
+        // exercise the real interlaced fallback without needing an Adam7-aware
+        // encoder.
+        let raw = vec![10u8, 20, 30]; // 1x1, 3 bytes/pixel
+        let png_data = encode_test_png(1, 1, png::ColorType::Rgb, None, None, true, &raw);
+
+        let assembled = load_image_assembled_streaming(&png_data).expect("decode failed");
+        let metadata = assembled.metadata();
+        assert_eq!(metadata.width(), 1);
+        assert_eq!(metadata.height(), 1);
+        assert_eq!(metadata.channels(), 3);
+        assert_eq!(assembled.data, raw);
+    }
 }